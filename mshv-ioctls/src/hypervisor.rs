@@ -0,0 +1,128 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+//! Backend-agnostic `Hypervisor`/`Vm`/`Vcpu` traits, selecting MSHV at build
+//! time behind the `hypervisor` feature.
+#![cfg(feature = "hypervisor")]
+
+use crate::ioctls::eventfd::{Datamatch, IoEventAddress, MsiRoutingEntry};
+use crate::ioctls::state::PartitionState;
+use crate::ioctls::system::HostPartitionProperty;
+use crate::ioctls::vm::VmType;
+use crate::ioctls::Result;
+#[cfg(target_arch = "x86_64")]
+use mshv_bindings::{LapicState, MsrList, Msrs};
+use mshv_bindings::{SpecialRegisters, StandardRegisters, SynicState};
+use vmm_sys_util::eventfd::EventFd;
+
+/// A hypervisor capable of creating VMs, mirroring [`Mshv`](crate::ioctls::system::Mshv).
+pub trait Hypervisor {
+    /// The concrete [`Vm`] type this hypervisor produces.
+    type Vm: Vm;
+
+    /// Creates a VM with default configuration.
+    fn create_vm(&self) -> Result<Self::Vm>;
+
+    /// Creates a VM of the given type.
+    fn create_vm_with_type(&self, vm_type: VmType) -> Result<Self::Vm>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Returns the list of MSRs this hypervisor supports for get/set.
+    fn get_msr_index_list(&self) -> Result<MsrList>;
+
+    /// Retrieves a host partition property.
+    fn get_host_partition_property(&self, property: HostPartitionProperty) -> Result<i32>;
+}
+
+/// A VM capable of creating vCPUs, mirroring [`VmFd`](crate::ioctls::vm::VmFd).
+pub trait Vm {
+    /// The concrete [`Vcpu`] type this VM produces.
+    type Vcpu: Vcpu;
+
+    /// Creates a vCPU with the given index.
+    fn create_vcpu(&self, id: u8) -> Result<Self::Vcpu>;
+
+    /// Sets a partition property using the strongly-typed [`HostPartitionProperty`] enum.
+    fn set_partition_property(&self, property: HostPartitionProperty, value: u64) -> Result<()>;
+
+    /// Captures a full snapshot of this partition's state. See
+    /// [`VmFd::save_state`](crate::ioctls::vm::VmFd::save_state).
+    fn save_state(
+        &self,
+        vcpus: &[Self::Vcpu],
+        msrs: &MsrList,
+        early_properties: &[HostPartitionProperty],
+    ) -> Result<PartitionState>;
+
+    /// Replays a snapshot captured by [`Vm::save_state`] onto this partition.
+    /// See [`VmFd::restore_state`](crate::ioctls::vm::VmFd::restore_state).
+    fn restore_state(&self, state: &PartitionState) -> Result<Vec<Self::Vcpu>>;
+
+    /// Binds `fd` to `gsi`. See
+    /// [`VmFd::register_irqfd`](crate::ioctls::vm::VmFd::register_irqfd).
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()>;
+
+    /// Unbinds a previously registered irqfd. See
+    /// [`VmFd::unregister_irqfd`](crate::ioctls::vm::VmFd::unregister_irqfd).
+    fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()>;
+
+    /// Binds `fd` to guest writes at `addr`. See
+    /// [`VmFd::register_ioeventfd`](crate::ioctls::vm::VmFd::register_ioeventfd).
+    fn register_ioeventfd(
+        &self,
+        fd: &EventFd,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()>;
+
+    /// Unbinds a previously registered ioeventfd. See
+    /// [`VmFd::unregister_ioeventfd`](crate::ioctls::vm::VmFd::unregister_ioeventfd).
+    fn unregister_ioeventfd(
+        &self,
+        fd: &EventFd,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()>;
+
+    /// Replaces this partition's GSI -> MSI message routing table. See
+    /// [`VmFd::set_msi_routing`](crate::ioctls::vm::VmFd::set_msi_routing).
+    fn set_msi_routing(&self, entries: &[MsiRoutingEntry]) -> Result<()>;
+}
+
+/// A virtual processor, mirroring [`VcpuFd`](crate::ioctls::vcpu::VcpuFd).
+pub trait Vcpu {
+    #[cfg(target_arch = "x86_64")]
+    /// Fills `msrs` with the current value of each MSR it lists an index for.
+    fn get_msrs(&self, msrs: &mut Msrs) -> Result<i32>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Sets the value of each MSR `msrs` lists an index and value for.
+    fn set_msrs(&self, msrs: &Msrs) -> Result<i32>;
+
+    /// Returns the standard (general-purpose + RIP/RFLAGS) register set.
+    fn get_regs(&self) -> Result<StandardRegisters>;
+
+    /// Sets the standard (general-purpose + RIP/RFLAGS) register set.
+    fn set_regs(&self, regs: &StandardRegisters) -> Result<()>;
+
+    /// Returns the special (segment/control) register set.
+    fn get_sregs(&self) -> Result<SpecialRegisters>;
+
+    /// Sets the special (segment/control) register set.
+    fn set_sregs(&self, sregs: &SpecialRegisters) -> Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Returns the local APIC state.
+    fn get_lapic(&self) -> Result<LapicState>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Sets the local APIC state.
+    fn set_lapic(&self, lapic: &LapicState) -> Result<()>;
+
+    /// Returns the synthetic interrupt controller (SynIC) state.
+    fn get_synic_state(&self) -> Result<SynicState>;
+
+    /// Sets the synthetic interrupt controller (SynIC) state.
+    fn set_synic_state(&self, synic: &SynicState) -> Result<()>;
+}
@@ -0,0 +1,67 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+//! Types for the eventfd/irqfd and MSI-routing ioctls surfaced on
+//! [`VmFd`](crate::ioctls::vm::VmFd).
+
+/// The guest address an ioeventfd is triggered by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoEventAddress {
+    /// Port I/O address.
+    Pio(u64),
+    /// MMIO guest physical address.
+    Mmio(u64),
+}
+
+/// The data an ioeventfd matches a guest write against, and the width of
+/// that write: real virtio notify writes are as narrow as 2 or 4 bytes, so
+/// the match width has to be part of the registration, not assumed to be 8.
+/// Only 4- and 8-byte value matches can be expressed; there is no 2-byte
+/// variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Datamatch {
+    /// Trigger on any write to the address, regardless of data.
+    NoDatamatch,
+    /// Trigger only on a 4-byte write equal to the given value.
+    DataMatch32(u32),
+    /// Trigger only on an 8-byte write equal to the given value.
+    DataMatch64(u64),
+}
+
+impl Datamatch {
+    /// Width, in bytes, of the write this datamatch applies to.
+    pub fn len(&self) -> u32 {
+        match self {
+            Datamatch::NoDatamatch => 0,
+            Datamatch::DataMatch32(_) => 4,
+            Datamatch::DataMatch64(_) => 8,
+        }
+    }
+
+    /// Returns `true` if this datamatch applies to a zero-length (any-data) write.
+    pub fn is_empty(&self) -> bool {
+        *self == Datamatch::NoDatamatch
+    }
+
+    /// The value to match, zero-extended to 64 bits.
+    pub fn data(&self) -> u64 {
+        match self {
+            Datamatch::NoDatamatch => 0,
+            Datamatch::DataMatch32(v) => u64::from(*v),
+            Datamatch::DataMatch64(v) => *v,
+        }
+    }
+}
+
+/// A single GSI -> MSI message routing entry, as set via
+/// [`VmFd::set_msi_routing`](crate::ioctls::vm::VmFd::set_msi_routing).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MsiRoutingEntry {
+    /// Global system interrupt this entry routes.
+    pub gsi: u32,
+    /// MSI message address (written to the device's address register).
+    pub address: u64,
+    /// MSI message data (written to the device's data register).
+    pub data: u32,
+}
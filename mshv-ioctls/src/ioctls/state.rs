@@ -0,0 +1,37 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::system::HostPartitionProperty;
+use mshv_bindings::*;
+
+/// Snapshot of a single VP's register and synthetic-interrupt state, as
+/// captured by [`VmFd::save_state`](crate::ioctls::vm::VmFd::save_state).
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VpState {
+    /// General-purpose registers and RIP/RFLAGS.
+    pub regs: StandardRegisters,
+    /// Segment, control and debug registers.
+    pub sregs: SpecialRegisters,
+    /// MSR values, in the order of the `MsrList` passed to `save_state`.
+    #[cfg(target_arch = "x86_64")]
+    pub msrs: Vec<msr_entry>,
+    /// Local APIC state.
+    #[cfg(target_arch = "x86_64")]
+    pub lapic: LapicState,
+    /// Synthetic interrupt controller (SynIC) state.
+    pub synic: SynicState,
+}
+
+/// Full snapshot of a partition's state: properties that must be restored
+/// before `initialize()`, plus per-VP state, replayed afterwards.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PartitionState {
+    /// Properties that must be set before `initialize()`, paired with their
+    /// captured values.
+    pub early_properties: Vec<(HostPartitionProperty, u64)>,
+    /// Per-VP state, in VP-index order.
+    pub vps: Vec<VpState>,
+}
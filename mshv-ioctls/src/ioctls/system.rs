@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 //
 use crate::ioctls::vm::{new_vmfd, VmFd, VmType};
+use crate::ioctls::vtl::{new_vtlfd, VtlFd};
 use crate::ioctls::Result;
 use crate::mshv_ioctls::*;
 use crate::*;
@@ -12,9 +13,54 @@ use std::fs::File;
 use std::os::raw::c_char;
 use std::os::unix::io::{FromRawFd, RawFd};
 use vmm_sys_util::errno;
-use vmm_sys_util::ioctl::ioctl_with_ref;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+
+/// Strongly-typed wrapper around the `hv_partition_property_code_HV_PARTITION_PROPERTY_*`
+/// constants, so callers of [`Mshv::get_host_partition_property`] and
+/// [`VmFd::set_partition_property`](crate::ioctls::vm::VmFd::set_partition_property)
+/// don't have to pass raw property codes around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "with-serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HostPartitionProperty {
+    /// Width, in bits, of the guest physical address space.
+    PhysicalAddressWidth,
+    /// Bitmap of `hv_partition_synthetic_processor_features` supported by the
+    /// partition. This is an 'early' property: it can only be set between
+    /// partition creation and [`VmFd::initialize`](crate::ioctls::vm::VmFd::initialize).
+    SyntheticProcessorFeatures,
+    /// First bank of host processor feature bits (IBRS/STIBP/MDD/etc.).
+    ProcessorFeatures,
+    /// Second bank of host processor feature bits (PSFD/etc.).
+    ProcessorFeatures1,
+}
+
+impl HostPartitionProperty {
+    /// Returns the raw `hv_partition_property_code` this variant wraps.
+    pub fn code(self) -> hv_partition_property_code {
+        match self {
+            HostPartitionProperty::PhysicalAddressWidth => {
+                hv_partition_property_code_HV_PARTITION_PROPERTY_PHYSICAL_ADDRESS_WIDTH
+            }
+            HostPartitionProperty::SyntheticProcessorFeatures => {
+                hv_partition_property_code_HV_PARTITION_PROPERTY_SYNTHETIC_PROC_FEATURES
+            }
+            HostPartitionProperty::ProcessorFeatures => {
+                hv_partition_property_code_HV_PARTITION_PROPERTY_PROCESSOR_FEATURES
+            }
+            HostPartitionProperty::ProcessorFeatures1 => {
+                hv_partition_property_code_HV_PARTITION_PROPERTY_PROCESSOR_FEATURES1
+            }
+        }
+    }
+}
 
 /// Wrapper over MSHV system ioctls.
+///
+/// This covers two distinct device paths exposed by the driver: `/dev/mshv`,
+/// used to manage root partitions (child VMs owned by the host), and
+/// `/dev/mshv_vtl`, used by paravisor-style VMMs running inside VTL2 to
+/// manage the lower-VTL guest alongside them. [`Mshv::new`] opens the former;
+/// [`Mshv::open_vtl`] opens the latter.
 #[derive(Debug)]
 pub struct Mshv {
     hv: File,
@@ -60,6 +106,30 @@ impl Mshv {
         }
     }
 
+    /// Opens `/dev/mshv_vtl` and returns a `VtlFd` object on success.
+    ///
+    /// This targets VMMs running inside VTL2 (paravisor-style), as opposed to
+    /// the root-partition child-VM path opened via [`Mshv::new`].
+    pub fn open_vtl(&self) -> Result<VtlFd> {
+        let fd = Self::open_vtl_with_cloexec(true)?;
+        // SAFETY: we verify that ret is valid and we own the fd.
+        let vtl_file = unsafe { File::from_raw_fd(fd) };
+        Ok(new_vtlfd(vtl_file))
+    }
+
+    /// Opens `/dev/mshv_vtl` and returns the fd number on success, using the
+    /// same `O_CLOEXEC`/`O_NONBLOCK` handling as [`Mshv::open_with_cloexec`].
+    pub fn open_vtl_with_cloexec(close_on_exec: bool) -> Result<RawFd> {
+        let open_flags = O_NONBLOCK | if close_on_exec { O_CLOEXEC } else { 0 };
+        // SAFETY: we give a constant null-terminated string and verify the result.
+        let ret = unsafe { open(c"/dev/mshv_vtl".as_ptr() as *const c_char, open_flags) };
+        if ret < 0 {
+            Err(errno::Error::last().into())
+        } else {
+            Ok(ret)
+        }
+    }
+
     /// Creates a VM fd using the MSHV fd and prepared mshv partition.
     pub fn create_vm_with_args(&self, args: &mshv_create_partition) -> Result<VmFd> {
         // SAFETY: IOCTL call with the correct types.
@@ -74,7 +144,13 @@ impl Mshv {
     }
 
     /// Retrieve the host partition property given a property code.
-    pub fn get_host_partition_property(&self, property_code: u64) -> Result<i32> {
+    ///
+    /// The value is returned truncated to 32 bits via the ioctl return code;
+    /// properties that are genuinely 64-bit (reference TSC frequency, GPA
+    /// page-access counts, processor feature bitmaps, ...) should use
+    /// [`Mshv::get_host_partition_property_u64`] instead.
+    pub fn get_host_partition_property(&self, property: HostPartitionProperty) -> Result<i32> {
+        let property_code = property.code() as u64;
         // SAFETY: IOCTL call with the correct types.
         let ret =
             unsafe { ioctl_with_ref(&self.hv, MSHV_GET_HOST_PARTITION_PROPERTY(), &property_code) };
@@ -85,6 +161,28 @@ impl Mshv {
         }
     }
 
+    /// Retrieve the full 64-bit value of a host partition property.
+    ///
+    /// Unlike [`Mshv::get_host_partition_property`], which truncates the
+    /// result to the ioctl's `i32` return code, this retrieves the property
+    /// value via an in/out argument so properties wider than 32 bits come
+    /// back intact.
+    pub fn get_host_partition_property_u64(&self, property: HostPartitionProperty) -> Result<u64> {
+        let mut args = mshv_get_host_partition_property {
+            property_code: property.code() as u64,
+            property_value: 0,
+        };
+        // SAFETY: IOCTL call with the correct types.
+        let ret = unsafe {
+            ioctl_with_mut_ref(&self.hv, MSHV_GET_HOST_PARTITION_PROPERTY64(), &mut args)
+        };
+        if ret == 0 {
+            Ok(args.property_value)
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
     /// Helper function to creates a VM fd using the MSHV fd with provided configuration.
     pub fn create_vm_with_type(&self, vm_type: VmType) -> Result<VmFd> {
         let mut features: hv_partition_synthetic_processor_features = Default::default();
@@ -126,8 +224,8 @@ impl Mshv {
         let vm = self.create_vm_with_args(&create_args)?;
 
         // This is an 'early' property that must be set between creation and initialization
-        vm.hvcall_set_partition_property(
-            hv_partition_property_code_HV_PARTITION_PROPERTY_SYNTHETIC_PROC_FEATURES,
+        vm.set_partition_property(
+            HostPartitionProperty::SyntheticProcessorFeatures,
             unsafe { features.as_uint64[0] },
         )?;
 
@@ -143,9 +241,29 @@ impl Mshv {
 
     #[cfg(target_arch = "x86_64")]
     /// X86 specific call to get list of supported MSRS
+    ///
+    /// Some MSRs are only safe to get/set when the host partition advertises
+    /// the corresponding processor feature (e.g. `IA32_MSR_BNDCFGS` requires
+    /// one of IBRS/STIBP/MDD/PSFD, `IA32_MSR_SPEC_CTRL` requires MPX). This
+    /// queries those feature bits via
+    /// [`get_host_partition_property_u64`](Mshv::get_host_partition_property_u64)
+    /// and only includes the feature-gated MSRs the host actually supports,
+    /// rather than relying on a hardcoded, version-specific list.
     pub fn get_msr_index_list(&self) -> Result<MsrList> {
-        /* return all the MSRs we currently support */
-        Ok(MsrList::from_entries(&[
+        let features0 =
+            self.get_host_partition_property_u64(HostPartitionProperty::ProcessorFeatures)?;
+        let features1 =
+            self.get_host_partition_property_u64(HostPartitionProperty::ProcessorFeatures1)?;
+
+        let bndcfgs_supported = (features0 & HV_X64_PROCESSOR_FEATURE0_IBRS) != 0
+            || (features0 & HV_X64_PROCESSOR_FEATURE0_STIBP) != 0
+            || (features0 & HV_X64_PROCESSOR_FEATURE0_MDD) != 0
+            || (features1 & HV_X64_PROCESSOR_FEATURE1_PSFD) != 0;
+        let spec_ctrl_supported = (features0 & HV_X64_PROCESSOR_FEATURE0_MPX) != 0;
+        let tsc_adjust_supported = (features0 & HV_X64_PROCESSOR_FEATURE0_TSC_ADJUST) != 0;
+
+        /* base set of MSRs supported regardless of processor features */
+        let mut msrs = vec![
             IA32_MSR_TSC,
             IA32_MSR_EFER,
             IA32_MSR_KERNEL_GS_BASE,
@@ -187,21 +305,7 @@ impl Mshv {
             IA32_MSR_MTRR_FIX4K_F0000,
             IA32_MSR_MTRR_FIX4K_F8000,
             IA32_MSR_TSC_AUX,
-            /*
-                IA32_MSR_BNDCFGS MSR can be accessed if any of the following features enabled
-                HV_X64_PROCESSOR_FEATURE0_IBRS
-                HV_X64_PROCESSOR_FEATURE0_STIBP
-                HV_X64_PROCESSOR_FEATURE0_MDD
-                HV_X64_PROCESSOR_FEATURE1_PSFD
-            */
-            //IA32_MSR_BNDCFGS,
             IA32_MSR_DEBUG_CTL,
-            /*
-                MPX support needed for this MSR
-                Currently feature is not enabled
-            */
-            //IA32_MSR_SPEC_CTRL,
-            //IA32_MSR_TSC_ADJUST, // Current hypervisor version does not allow to get this MSR, need to check later
             HV_X64_MSR_GUEST_OS_ID,
             HV_X64_MSR_SINT0,
             HV_X64_MSR_SINT1,
@@ -224,8 +328,51 @@ impl Mshv {
             HV_X64_MSR_SIMP,
             HV_X64_MSR_REFERENCE_TSC,
             HV_X64_MSR_EOM,
-        ])
-        .unwrap())
+        ];
+
+        /*
+            IA32_MSR_BNDCFGS MSR can be accessed if any of the following features enabled
+            HV_X64_PROCESSOR_FEATURE0_IBRS
+            HV_X64_PROCESSOR_FEATURE0_STIBP
+            HV_X64_PROCESSOR_FEATURE0_MDD
+            HV_X64_PROCESSOR_FEATURE1_PSFD
+        */
+        if bndcfgs_supported {
+            msrs.push(IA32_MSR_BNDCFGS);
+        }
+
+        /* MPX support needed for this MSR */
+        if spec_ctrl_supported {
+            msrs.push(IA32_MSR_SPEC_CTRL);
+        }
+
+        if tsc_adjust_supported {
+            msrs.push(IA32_MSR_TSC_ADJUST);
+        }
+
+        Ok(MsrList::from_entries(&msrs).unwrap())
+    }
+}
+
+#[cfg(feature = "hypervisor")]
+impl crate::hypervisor::Hypervisor for Mshv {
+    type Vm = VmFd;
+
+    fn create_vm(&self) -> Result<Self::Vm> {
+        Mshv::create_vm(self)
+    }
+
+    fn create_vm_with_type(&self, vm_type: VmType) -> Result<Self::Vm> {
+        Mshv::create_vm_with_type(self, vm_type)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_msr_index_list(&self) -> Result<MsrList> {
+        Mshv::get_msr_index_list(self)
+    }
+
+    fn get_host_partition_property(&self, property: HostPartitionProperty) -> Result<i32> {
+        Mshv::get_host_partition_property(self, property)
     }
 }
 
@@ -246,9 +393,8 @@ mod tests {
     #[test]
     fn test_get_host_ipa_limit() {
         let hv = Mshv::new().unwrap();
-        let host_ipa_limit = hv.get_host_partition_property(
-            hv_partition_property_code_HV_PARTITION_PROPERTY_PHYSICAL_ADDRESS_WIDTH as u64,
-        );
+        let host_ipa_limit =
+            hv.get_host_partition_property(HostPartitionProperty::PhysicalAddressWidth);
         assert!(host_ipa_limit.is_ok());
     }
 
@@ -266,7 +412,9 @@ mod tests {
     fn test_get_msr_index_list() {
         let hv = Mshv::new().unwrap();
         let msr_list = hv.get_msr_index_list().unwrap();
-        assert!(msr_list.as_fam_struct_ref().nmsrs == 64);
+        // The exact count depends on which feature-gated MSRs the host
+        // partition supports, so just check the list is non-empty.
+        assert!(msr_list.as_fam_struct_ref().nmsrs > 0);
 
         let mut found = false;
         for index in msr_list.as_slice() {
@@ -300,4 +448,84 @@ mod tests {
         }
         assert!(num_errors == 0);
     }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    #[ignore]
+    fn test_save_restore_state() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        // Give the VP recognizable register and segment state so the
+        // round-trip below can actually prove something was preserved,
+        // rather than just that a VP came back.
+        let mut regs = vcpu.get_regs().unwrap();
+        regs.rax = 0x1234_5678_9abc_def0;
+        vcpu.set_regs(&regs).unwrap();
+
+        let mut sregs = vcpu.get_sregs().unwrap();
+        sregs.cs.selector = 0xf000;
+        sregs.cs.base = 0xf0000;
+        vcpu.set_sregs(&sregs).unwrap();
+
+        let msrs = hv.get_msr_index_list().unwrap();
+        let early_properties = [HostPartitionProperty::SyntheticProcessorFeatures];
+
+        let state = vm
+            .save_state(&[vcpu], &msrs, &early_properties)
+            .unwrap();
+        assert_eq!(state.vps.len(), 1);
+        assert_eq!(state.vps[0].regs.rax, regs.rax);
+        assert_eq!(state.vps[0].sregs.cs.selector, sregs.cs.selector);
+        assert_eq!(state.vps[0].sregs.cs.base, sregs.cs.base);
+
+        let pr: mshv_create_partition = Default::default();
+        let restored_vm = hv.create_vm_with_args(&pr).unwrap();
+        let vcpus = restored_vm.restore_state(&state).unwrap();
+        assert_eq!(vcpus.len(), 1);
+
+        let restored_regs = vcpus[0].get_regs().unwrap();
+        assert_eq!(restored_regs.rax, regs.rax);
+        let restored_sregs = vcpus[0].get_sregs().unwrap();
+        assert_eq!(restored_sregs.cs.selector, sregs.cs.selector);
+        assert_eq!(restored_sregs.cs.base, sregs.cs.base);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_irqfd_ioeventfd_msi_routing() {
+        use crate::ioctls::eventfd::{Datamatch, IoEventAddress, MsiRoutingEntry};
+        use vmm_sys_util::eventfd::EventFd;
+
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+
+        let irqfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        vm.register_irqfd(&irqfd, 0).unwrap();
+        vm.unregister_irqfd(&irqfd, 0).unwrap();
+
+        // A 4-byte match, the width a legacy virtio notify write uses.
+        let ioeventfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let addr = IoEventAddress::Mmio(0x1000);
+        let datamatch = Datamatch::DataMatch32(1);
+        vm.register_ioeventfd(&ioeventfd, addr, datamatch).unwrap();
+        vm.unregister_ioeventfd(&ioeventfd, addr, datamatch).unwrap();
+
+        vm.set_msi_routing(&[MsiRoutingEntry {
+            gsi: 0,
+            address: 0xfee0_0000,
+            data: 0,
+        }])
+        .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn test_open_vtl_create_vp() {
+        let hv = Mshv::new().unwrap();
+        let vtl = hv.open_vtl().unwrap();
+        let vtl_vp = vtl.create_vtl_vp(0);
+        assert!(vtl_vp.is_ok());
+    }
 }
@@ -0,0 +1,511 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::Result;
+use crate::mshv_ioctls::*;
+use mshv_bindings::*;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, RawFd};
+use vmm_sys_util::errno;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+
+/// Wrapper over a virtual processor (VP) fd, created via
+/// [`VmFd::create_vcpu`](crate::ioctls::vm::VmFd::create_vcpu).
+#[derive(Debug)]
+pub struct VcpuFd {
+    vcpu: File,
+}
+
+/// Helper function to create a new `VcpuFd` from an open VP file.
+pub fn new_vcpufd(vcpu: File) -> VcpuFd {
+    VcpuFd { vcpu }
+}
+
+impl VcpuFd {
+    /// Returns the raw fd backing this `VcpuFd`.
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.vcpu.as_raw_fd()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    /// Fills `msrs` with the current value of each MSR it lists an index for.
+    pub fn get_msrs(&self, msrs: &mut Msrs) -> Result<i32> {
+        let nmsrs = msrs.as_fam_struct_ref().nmsrs;
+        // SAFETY: IOCTL call with the correct types.
+        let ret = unsafe {
+            ioctl_with_mut_ref(&self.vcpu, MSHV_GET_VP_REGISTERS(), msrs.as_mut_fam_struct_ptr())
+        };
+        if ret < 0 {
+            return Err(errno::Error::last().into());
+        }
+        Ok(nmsrs as i32)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    /// Sets the value of each MSR `msrs` lists an index and value for.
+    pub fn set_msrs(&self, msrs: &Msrs) -> Result<i32> {
+        let nmsrs = msrs.as_fam_struct_ref().nmsrs;
+        // SAFETY: IOCTL call with the correct types.
+        let ret = unsafe {
+            ioctl_with_ref(&self.vcpu, MSHV_SET_VP_REGISTERS(), msrs.as_fam_struct_ptr())
+        };
+        if ret < 0 {
+            return Err(errno::Error::last().into());
+        }
+        Ok(nmsrs as i32)
+    }
+
+    /// Fills the `hv_register_assoc` entries in `assocs` with their current
+    /// values; each entry's `name` selects which register is read.
+    ///
+    /// This is the same "list of named register assocs" calling convention
+    /// [`VcpuFd::get_msrs`] uses for MSRs: `MSHV_GET_VP_REGISTERS` always
+    /// operates on such a list, never on a single fixed C struct.
+    fn get_reg_assocs(&self, assocs: &mut [hv_register_assoc]) -> Result<()> {
+        let args = mshv_vp_registers {
+            count: assocs.len() as u32,
+            regs: assocs.as_mut_ptr() as u64,
+        };
+        // SAFETY: IOCTL call with the correct types; `regs` points at
+        // `assocs`, which outlives the call.
+        let ret = unsafe { ioctl_with_ref(&self.vcpu, MSHV_GET_VP_REGISTERS(), &args) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
+    /// Sets each register named by an entry in `assocs` to that entry's value.
+    fn set_reg_assocs(&self, assocs: &[hv_register_assoc]) -> Result<()> {
+        let args = mshv_vp_registers {
+            count: assocs.len() as u32,
+            regs: assocs.as_ptr() as u64,
+        };
+        // SAFETY: IOCTL call with the correct types; `regs` points at
+        // `assocs`, which outlives the call.
+        let ret = unsafe { ioctl_with_ref(&self.vcpu, MSHV_SET_VP_REGISTERS(), &args) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
+    /// Returns the standard (general-purpose + RIP/RFLAGS) register set.
+    pub fn get_regs(&self) -> Result<StandardRegisters> {
+        let mut assocs = standard_register_assocs(&StandardRegisters::default());
+        self.get_reg_assocs(&mut assocs)?;
+        Ok(standard_regs_from_assocs(&assocs))
+    }
+
+    /// Sets the standard (general-purpose + RIP/RFLAGS) register set.
+    pub fn set_regs(&self, regs: &StandardRegisters) -> Result<()> {
+        self.set_reg_assocs(&standard_register_assocs(regs))
+    }
+
+    /// Returns the special (segment/control) register set.
+    pub fn get_sregs(&self) -> Result<SpecialRegisters> {
+        let mut assocs = special_register_assocs(&SpecialRegisters::default());
+        self.get_reg_assocs(&mut assocs)?;
+        Ok(special_regs_from_assocs(&assocs))
+    }
+
+    /// Sets the special (segment/control) register set.
+    pub fn set_sregs(&self, sregs: &SpecialRegisters) -> Result<()> {
+        self.set_reg_assocs(&special_register_assocs(sregs))
+    }
+
+    /// Reads a type-tagged piece of VP state (LAPIC, SynIC, ...) into `buf`.
+    ///
+    /// `MSHV_GET_VP_STATE`/`MSHV_SET_VP_STATE` need a discriminant to know
+    /// which blob they're filling in or reading, since they're shared by
+    /// every kind of VP state: `state_type` carries that discriminant, and
+    /// `buf` must be sized for exactly that state type.
+    fn get_vp_state(&self, state_type: u32, buf: &mut [u8]) -> Result<()> {
+        let args = mshv_get_set_vp_state {
+            type_: state_type,
+            buf_sz: buf.len() as u32,
+            buf_ptr: buf.as_mut_ptr() as u64,
+        };
+        // SAFETY: IOCTL call with the correct types; `buf_ptr` points at
+        // `buf`, which outlives the call.
+        let ret = unsafe { ioctl_with_ref(&self.vcpu, MSHV_GET_VP_STATE(), &args) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
+    /// Writes a type-tagged piece of VP state (LAPIC, SynIC, ...) from `buf`.
+    fn set_vp_state(&self, state_type: u32, buf: &[u8]) -> Result<()> {
+        let args = mshv_get_set_vp_state {
+            type_: state_type,
+            buf_sz: buf.len() as u32,
+            buf_ptr: buf.as_ptr() as u64,
+        };
+        // SAFETY: IOCTL call with the correct types; `buf_ptr` points at
+        // `buf`, which outlives the call.
+        let ret = unsafe { ioctl_with_ref(&self.vcpu, MSHV_SET_VP_STATE(), &args) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    /// Returns the local APIC state.
+    pub fn get_lapic(&self) -> Result<LapicState> {
+        let mut lapic = LapicState::default();
+        // SAFETY: `LapicState` is a plain-old-data struct and `get_vp_state`
+        // only ever writes `size_of::<LapicState>()` bytes into it.
+        let buf = unsafe {
+            std::slice::from_raw_parts_mut(
+                &mut lapic as *mut LapicState as *mut u8,
+                std::mem::size_of::<LapicState>(),
+            )
+        };
+        self.get_vp_state(MSHV_VP_STATE_LAPIC, buf)?;
+        Ok(lapic)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    /// Sets the local APIC state.
+    pub fn set_lapic(&self, lapic: &LapicState) -> Result<()> {
+        // SAFETY: `LapicState` is a plain-old-data struct.
+        let buf = unsafe {
+            std::slice::from_raw_parts(
+                lapic as *const LapicState as *const u8,
+                std::mem::size_of::<LapicState>(),
+            )
+        };
+        self.set_vp_state(MSHV_VP_STATE_LAPIC, buf)
+    }
+
+    /// Returns the synthetic interrupt controller (SynIC) state.
+    pub fn get_synic_state(&self) -> Result<SynicState> {
+        let mut synic = SynicState::default();
+        // SAFETY: `SynicState` is a plain-old-data struct and `get_vp_state`
+        // only ever writes `size_of::<SynicState>()` bytes into it.
+        let buf = unsafe {
+            std::slice::from_raw_parts_mut(
+                &mut synic as *mut SynicState as *mut u8,
+                std::mem::size_of::<SynicState>(),
+            )
+        };
+        self.get_vp_state(MSHV_VP_STATE_SYNIC, buf)?;
+        Ok(synic)
+    }
+
+    /// Sets the synthetic interrupt controller (SynIC) state.
+    pub fn set_synic_state(&self, synic: &SynicState) -> Result<()> {
+        // SAFETY: `SynicState` is a plain-old-data struct.
+        let buf = unsafe {
+            std::slice::from_raw_parts(
+                synic as *const SynicState as *const u8,
+                std::mem::size_of::<SynicState>(),
+            )
+        };
+        self.set_vp_state(MSHV_VP_STATE_SYNIC, buf)
+    }
+}
+
+/// `hv_register_name`s making up [`StandardRegisters`], in the same order
+/// `standard_register_assocs`/`standard_regs_from_assocs` read and write them.
+#[cfg(target_arch = "x86_64")]
+const STANDARD_REGISTER_NAMES: [hv_register_name; 18] = [
+    hv_register_name_HV_X64_REGISTER_RAX,
+    hv_register_name_HV_X64_REGISTER_RBX,
+    hv_register_name_HV_X64_REGISTER_RCX,
+    hv_register_name_HV_X64_REGISTER_RDX,
+    hv_register_name_HV_X64_REGISTER_RSI,
+    hv_register_name_HV_X64_REGISTER_RDI,
+    hv_register_name_HV_X64_REGISTER_RSP,
+    hv_register_name_HV_X64_REGISTER_RBP,
+    hv_register_name_HV_X64_REGISTER_R8,
+    hv_register_name_HV_X64_REGISTER_R9,
+    hv_register_name_HV_X64_REGISTER_R10,
+    hv_register_name_HV_X64_REGISTER_R11,
+    hv_register_name_HV_X64_REGISTER_R12,
+    hv_register_name_HV_X64_REGISTER_R13,
+    hv_register_name_HV_X64_REGISTER_R14,
+    hv_register_name_HV_X64_REGISTER_R15,
+    hv_register_name_HV_X64_REGISTER_RIP,
+    hv_register_name_HV_X64_REGISTER_RFLAGS,
+];
+
+#[cfg(target_arch = "x86_64")]
+fn reg_assoc(name: hv_register_name, value: u64) -> hv_register_assoc {
+    let mut assoc = hv_register_assoc {
+        name: name as u32,
+        ..Default::default()
+    };
+    assoc.value.reg64 = value;
+    assoc
+}
+
+#[cfg(target_arch = "x86_64")]
+fn reg64(assoc: &hv_register_assoc) -> u64 {
+    // SAFETY: every register this file reads back is a plain 64-bit value.
+    unsafe { assoc.value.reg64 }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn standard_register_assocs(regs: &StandardRegisters) -> Vec<hv_register_assoc> {
+    let values = [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rsp, regs.rbp, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+        regs.rflags,
+    ];
+    STANDARD_REGISTER_NAMES
+        .iter()
+        .zip(values.iter())
+        .map(|(name, value)| reg_assoc(*name, *value))
+        .collect()
+}
+
+#[cfg(target_arch = "x86_64")]
+fn standard_regs_from_assocs(assocs: &[hv_register_assoc]) -> StandardRegisters {
+    let v: Vec<u64> = assocs.iter().map(reg64).collect();
+    StandardRegisters {
+        rax: v[0],
+        rbx: v[1],
+        rcx: v[2],
+        rdx: v[3],
+        rsi: v[4],
+        rdi: v[5],
+        rsp: v[6],
+        rbp: v[7],
+        r8: v[8],
+        r9: v[9],
+        r10: v[10],
+        r11: v[11],
+        r12: v[12],
+        r13: v[13],
+        r14: v[14],
+        r15: v[15],
+        rip: v[16],
+        rflags: v[17],
+    }
+}
+
+/// `hv_register_name`s making up [`SpecialRegisters`]' control registers, in
+/// the same order `special_register_assocs`/`special_regs_from_assocs` read
+/// and write them. Segment and table registers are handled separately since
+/// they carry more than a plain 64-bit value.
+#[cfg(target_arch = "x86_64")]
+const SPECIAL_CONTROL_REGISTER_NAMES: [hv_register_name; 6] = [
+    hv_register_name_HV_X64_REGISTER_CR0,
+    hv_register_name_HV_X64_REGISTER_CR2,
+    hv_register_name_HV_X64_REGISTER_CR3,
+    hv_register_name_HV_X64_REGISTER_CR4,
+    hv_register_name_HV_X64_REGISTER_CR8,
+    hv_register_name_HV_X64_REGISTER_EFER,
+];
+
+/// `hv_register_name`s making up [`SpecialRegisters`]' segment registers, in
+/// the same order `special_register_assocs`/`special_regs_from_assocs` read
+/// and write them.
+#[cfg(target_arch = "x86_64")]
+const SEGMENT_REGISTER_NAMES: [hv_register_name; 8] = [
+    hv_register_name_HV_X64_REGISTER_CS,
+    hv_register_name_HV_X64_REGISTER_DS,
+    hv_register_name_HV_X64_REGISTER_ES,
+    hv_register_name_HV_X64_REGISTER_FS,
+    hv_register_name_HV_X64_REGISTER_GS,
+    hv_register_name_HV_X64_REGISTER_SS,
+    hv_register_name_HV_X64_REGISTER_TR,
+    hv_register_name_HV_X64_REGISTER_LDTR,
+];
+
+/// `hv_register_name`s making up [`SpecialRegisters`]' descriptor tables, in
+/// the same order `special_register_assocs`/`special_regs_from_assocs` read
+/// and write them.
+#[cfg(target_arch = "x86_64")]
+const TABLE_REGISTER_NAMES: [hv_register_name; 2] = [
+    hv_register_name_HV_X64_REGISTER_GDTR,
+    hv_register_name_HV_X64_REGISTER_IDTR,
+];
+
+/// Packs a [`SegmentRegister`]'s flag fields into the `attributes` bitfield
+/// `hv_x64_segment_register` expects, matching the layout of the x86 segment
+/// descriptor's access-rights byte plus granularity/default/long bits.
+#[cfg(target_arch = "x86_64")]
+fn segment_attributes(seg: &SegmentRegister) -> u16 {
+    (seg.type_ as u16 & 0xf)
+        | ((seg.s as u16 & 0x1) << 4)
+        | ((seg.dpl as u16 & 0x3) << 5)
+        | ((seg.present as u16 & 0x1) << 7)
+        | ((seg.avl as u16 & 0x1) << 12)
+        | ((seg.l as u16 & 0x1) << 13)
+        | ((seg.db as u16 & 0x1) << 14)
+        | ((seg.g as u16 & 0x1) << 15)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn segment_reg_assoc(name: hv_register_name, seg: &SegmentRegister) -> hv_register_assoc {
+    let mut assoc = hv_register_assoc {
+        name: name as u32,
+        ..Default::default()
+    };
+    assoc.value.segment = hv_x64_segment_register {
+        base: seg.base,
+        limit: seg.limit,
+        selector: seg.selector,
+        attributes: segment_attributes(seg),
+    };
+    assoc
+}
+
+#[cfg(target_arch = "x86_64")]
+fn segment_from_assoc(assoc: &hv_register_assoc) -> SegmentRegister {
+    // SAFETY: every assoc built by `segment_reg_assoc`/read back here was
+    // populated through the `segment` union variant.
+    let segment = unsafe { assoc.value.segment };
+    SegmentRegister {
+        base: segment.base,
+        limit: segment.limit,
+        selector: segment.selector,
+        type_: (segment.attributes & 0xf) as u8,
+        s: ((segment.attributes >> 4) & 0x1) as u8,
+        dpl: ((segment.attributes >> 5) & 0x3) as u8,
+        present: ((segment.attributes >> 7) & 0x1) as u8,
+        avl: ((segment.attributes >> 12) & 0x1) as u8,
+        l: ((segment.attributes >> 13) & 0x1) as u8,
+        db: ((segment.attributes >> 14) & 0x1) as u8,
+        g: ((segment.attributes >> 15) & 0x1) as u8,
+        unusable: 0,
+        ..Default::default()
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn table_reg_assoc(name: hv_register_name, table: &DescriptorTable) -> hv_register_assoc {
+    let mut assoc = hv_register_assoc {
+        name: name as u32,
+        ..Default::default()
+    };
+    assoc.value.table = hv_x64_table_register {
+        pad: Default::default(),
+        limit: table.limit,
+        base: table.base,
+    };
+    assoc
+}
+
+#[cfg(target_arch = "x86_64")]
+fn table_from_assoc(assoc: &hv_register_assoc) -> DescriptorTable {
+    // SAFETY: every assoc built by `table_reg_assoc`/read back here was
+    // populated through the `table` union variant.
+    let table = unsafe { assoc.value.table };
+    DescriptorTable {
+        base: table.base,
+        limit: table.limit,
+        ..Default::default()
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn special_register_assocs(sregs: &SpecialRegisters) -> Vec<hv_register_assoc> {
+    let control_values = [
+        sregs.cr0, sregs.cr2, sregs.cr3, sregs.cr4, sregs.cr8, sregs.efer,
+    ];
+    let mut assocs: Vec<hv_register_assoc> = SPECIAL_CONTROL_REGISTER_NAMES
+        .iter()
+        .zip(control_values.iter())
+        .map(|(name, value)| reg_assoc(*name, *value))
+        .collect();
+
+    let segments = [
+        &sregs.cs, &sregs.ds, &sregs.es, &sregs.fs, &sregs.gs, &sregs.ss, &sregs.tr, &sregs.ldt,
+    ];
+    assocs.extend(
+        SEGMENT_REGISTER_NAMES
+            .iter()
+            .zip(segments.iter())
+            .map(|(name, seg)| segment_reg_assoc(*name, seg)),
+    );
+
+    let tables = [&sregs.gdt, &sregs.idt];
+    assocs.extend(
+        TABLE_REGISTER_NAMES
+            .iter()
+            .zip(tables.iter())
+            .map(|(name, table)| table_reg_assoc(*name, table)),
+    );
+
+    assocs
+}
+
+#[cfg(target_arch = "x86_64")]
+fn special_regs_from_assocs(assocs: &[hv_register_assoc]) -> SpecialRegisters {
+    SpecialRegisters {
+        cr0: reg64(&assocs[0]),
+        cr2: reg64(&assocs[1]),
+        cr3: reg64(&assocs[2]),
+        cr4: reg64(&assocs[3]),
+        cr8: reg64(&assocs[4]),
+        efer: reg64(&assocs[5]),
+        cs: segment_from_assoc(&assocs[6]),
+        ds: segment_from_assoc(&assocs[7]),
+        es: segment_from_assoc(&assocs[8]),
+        fs: segment_from_assoc(&assocs[9]),
+        gs: segment_from_assoc(&assocs[10]),
+        ss: segment_from_assoc(&assocs[11]),
+        tr: segment_from_assoc(&assocs[12]),
+        ldt: segment_from_assoc(&assocs[13]),
+        gdt: table_from_assoc(&assocs[14]),
+        idt: table_from_assoc(&assocs[15]),
+        ..Default::default()
+    }
+}
+
+#[cfg(feature = "hypervisor")]
+impl crate::hypervisor::Vcpu for VcpuFd {
+    #[cfg(target_arch = "x86_64")]
+    fn get_msrs(&self, msrs: &mut Msrs) -> Result<i32> {
+        VcpuFd::get_msrs(self, msrs)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_msrs(&self, msrs: &Msrs) -> Result<i32> {
+        VcpuFd::set_msrs(self, msrs)
+    }
+
+    fn get_regs(&self) -> Result<StandardRegisters> {
+        VcpuFd::get_regs(self)
+    }
+
+    fn set_regs(&self, regs: &StandardRegisters) -> Result<()> {
+        VcpuFd::set_regs(self, regs)
+    }
+
+    fn get_sregs(&self) -> Result<SpecialRegisters> {
+        VcpuFd::get_sregs(self)
+    }
+
+    fn set_sregs(&self, sregs: &SpecialRegisters) -> Result<()> {
+        VcpuFd::set_sregs(self, sregs)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_lapic(&self) -> Result<LapicState> {
+        VcpuFd::get_lapic(self)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_lapic(&self, lapic: &LapicState) -> Result<()> {
+        VcpuFd::set_lapic(self, lapic)
+    }
+
+    fn get_synic_state(&self) -> Result<SynicState> {
+        VcpuFd::get_synic_state(self)
+    }
+
+    fn set_synic_state(&self, synic: &SynicState) -> Result<()> {
+        VcpuFd::set_synic_state(self, synic)
+    }
+}
@@ -0,0 +1,384 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::eventfd::{Datamatch, IoEventAddress, MsiRoutingEntry};
+use crate::ioctls::state::{PartitionState, VpState};
+use crate::ioctls::system::HostPartitionProperty;
+use crate::ioctls::vcpu::{new_vcpufd, VcpuFd};
+use crate::ioctls::Result;
+use crate::mshv_ioctls::*;
+use mshv_bindings::*;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use vmm_sys_util::errno;
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref};
+
+/// Distinguishes the kind of partition created via [`Mshv::create_vm_with_type`](crate::ioctls::system::Mshv::create_vm_with_type).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmType {
+    /// A normal, non-isolated partition.
+    Normal,
+    /// An AMD SEV-SNP isolated partition.
+    Snp,
+}
+
+/// Wrapper over a root-partition VM fd, created via
+/// [`Mshv::create_vm`](crate::ioctls::system::Mshv::create_vm) or
+/// [`Mshv::create_vm_with_args`](crate::ioctls::system::Mshv::create_vm_with_args).
+#[derive(Debug)]
+pub struct VmFd {
+    vm: File,
+}
+
+/// Helper function to create a new `VmFd` from an open partition file.
+pub fn new_vmfd(vm: File) -> VmFd {
+    VmFd { vm }
+}
+
+impl VmFd {
+    /// Returns the raw fd backing this `VmFd`.
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.vm.as_raw_fd()
+    }
+
+    /// Finalizes partition creation.
+    ///
+    /// Must be called after all 'early' partition properties (those that can
+    /// only be set between creation and initialization, like
+    /// `SYNTHETIC_PROC_FEATURES`) have been set via
+    /// [`VmFd::set_partition_property`] / [`VmFd::hvcall_set_partition_property`].
+    pub fn initialize(&self) -> Result<()> {
+        // SAFETY: IOCTL call with no arguments.
+        let ret = unsafe { ioctl(&self.vm, MSHV_INITIALIZE_PARTITION()) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
+    /// Creates a VP with the given index.
+    pub fn create_vcpu(&self, id: u8) -> Result<VcpuFd> {
+        let args = mshv_create_vp {
+            vp_index: id as u32,
+        };
+        // SAFETY: IOCTL call with the correct types.
+        let ret = unsafe { ioctl_with_ref(&self.vm, MSHV_CREATE_VP(), &args) };
+        if ret >= 0 {
+            // SAFETY: we verify the value of ret and we are the owners of the fd.
+            let vp_file = unsafe { File::from_raw_fd(ret) };
+            Ok(new_vcpufd(vp_file))
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
+    /// Sets a partition property using a raw `hv_partition_property_code`.
+    pub fn hvcall_set_partition_property(
+        &self,
+        property_code: hv_partition_property_code,
+        property_value: u64,
+    ) -> Result<()> {
+        let args = mshv_set_partition_property {
+            property_code: property_code as u64,
+            property_value,
+        };
+        // SAFETY: IOCTL call with the correct types.
+        let ret = unsafe { ioctl_with_ref(&self.vm, MSHV_SET_PARTITION_PROPERTY(), &args) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
+    /// Gets a partition property using a raw `hv_partition_property_code`.
+    pub fn hvcall_get_partition_property(
+        &self,
+        property_code: hv_partition_property_code,
+    ) -> Result<u64> {
+        let mut args = mshv_get_partition_property {
+            property_code: property_code as u64,
+            property_value: 0,
+        };
+        // SAFETY: IOCTL call with the correct types.
+        let ret =
+            unsafe { ioctl_with_mut_ref(&self.vm, MSHV_GET_PARTITION_PROPERTY(), &mut args) };
+        if ret == 0 {
+            Ok(args.property_value)
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
+    /// Sets a partition property using the strongly-typed
+    /// [`HostPartitionProperty`] enum.
+    ///
+    /// This is how 'early' properties (those that can only be set between
+    /// partition creation and [`VmFd::initialize`], like
+    /// `SyntheticProcessorFeatures`) are applied.
+    pub fn set_partition_property(&self, property: HostPartitionProperty, value: u64) -> Result<()> {
+        self.hvcall_set_partition_property(property.code(), value)
+    }
+
+    /// Gets a partition property using the strongly-typed
+    /// [`HostPartitionProperty`] enum.
+    pub fn get_partition_property(&self, property: HostPartitionProperty) -> Result<u64> {
+        self.hvcall_get_partition_property(property.code())
+    }
+
+    /// Captures a full snapshot of this partition's state, for save-state or
+    /// live migration.
+    ///
+    /// `early_properties` should list the 'early' properties to capture
+    /// (e.g. `SyntheticProcessorFeatures`); `msrs` and `vcpus` should cover
+    /// every VP this partition owns, typically obtained via
+    /// `Mshv::get_msr_index_list` and the `VcpuFd`s returned by
+    /// [`VmFd::create_vcpu`].
+    pub fn save_state(
+        &self,
+        vcpus: &[VcpuFd],
+        msrs: &MsrList,
+        early_properties: &[HostPartitionProperty],
+    ) -> Result<PartitionState> {
+        let mut saved_properties = Vec::with_capacity(early_properties.len());
+        for property in early_properties {
+            saved_properties.push((*property, self.get_partition_property(*property)?));
+        }
+
+        let msr_entries: Vec<msr_entry> = msrs
+            .as_slice()
+            .iter()
+            .map(|index| msr_entry {
+                index: *index,
+                ..Default::default()
+            })
+            .collect();
+
+        let mut vps = Vec::with_capacity(vcpus.len());
+        for vcpu in vcpus {
+            let mut vp_msrs = Msrs::from_entries(&msr_entries).unwrap();
+            vcpu.get_msrs(&mut vp_msrs)?;
+            vps.push(VpState {
+                regs: vcpu.get_regs()?,
+                sregs: vcpu.get_sregs()?,
+                msrs: vp_msrs.as_slice().to_vec(),
+                lapic: vcpu.get_lapic()?,
+                synic: vcpu.get_synic_state()?,
+            });
+        }
+
+        Ok(PartitionState {
+            early_properties: saved_properties,
+            vps,
+        })
+    }
+
+    /// Replays a snapshot captured by [`VmFd::save_state`] onto this
+    /// partition, creating its VPs along the way.
+    ///
+    /// `self` must be a freshly created, not-yet-initialized partition:
+    /// `early_properties` are restored before [`VmFd::initialize`], matching
+    /// the window they were originally set in, and per-VP state is restored
+    /// afterwards.
+    pub fn restore_state(&self, state: &PartitionState) -> Result<Vec<VcpuFd>> {
+        for (property, value) in &state.early_properties {
+            self.set_partition_property(*property, *value)?;
+        }
+        self.initialize()?;
+
+        let mut vcpus = Vec::with_capacity(state.vps.len());
+        for (index, vp) in state.vps.iter().enumerate() {
+            let vcpu = self.create_vcpu(index as u8)?;
+            vcpu.set_regs(&vp.regs)?;
+            vcpu.set_sregs(&vp.sregs)?;
+            vcpu.set_msrs(&Msrs::from_entries(&vp.msrs).unwrap())?;
+            vcpu.set_lapic(&vp.lapic)?;
+            vcpu.set_synic_state(&vp.synic)?;
+            vcpus.push(vcpu);
+        }
+        Ok(vcpus)
+    }
+
+    /// Binds `fd` to `gsi`: instead of issuing a synchronous hypercall,
+    /// userspace can simply `write` to `fd` to have the hypervisor inject
+    /// the corresponding interrupt into the guest.
+    pub fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        let args = mshv_user_irqfd {
+            fd: fd.as_raw_fd() as u32,
+            gsi,
+            flags: 0,
+        };
+        // SAFETY: IOCTL call with the correct types.
+        let ret = unsafe { ioctl_with_ref(&self.vm, MSHV_SET_IRQFD(), &args) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
+    /// Unbinds a previously registered irqfd.
+    pub fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        let args = mshv_user_irqfd {
+            fd: fd.as_raw_fd() as u32,
+            gsi,
+            flags: MSHV_IRQFD_FLAG_DEASSIGN,
+        };
+        // SAFETY: IOCTL call with the correct types.
+        let ret = unsafe { ioctl_with_ref(&self.vm, MSHV_SET_IRQFD(), &args) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
+    /// Binds `fd` so that a guest write to `addr` matching `datamatch`
+    /// triggers it, without trapping back out to userspace synchronously.
+    ///
+    /// `datamatch`'s variant also carries the width of the write it applies
+    /// to (4 or 8 bytes; there is no 2-byte variant), unlike a plain match
+    /// value, which can't convey that on its own.
+    pub fn register_ioeventfd(
+        &self,
+        fd: &EventFd,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        self.ioeventfd_config(fd, addr, datamatch, 0)
+    }
+
+    /// Unbinds a previously registered ioeventfd.
+    ///
+    /// `datamatch` must match what the ioeventfd was registered with: the
+    /// kernel deduplicates on the full registration key, datamatch (and its
+    /// width) included, so a mismatched value here won't find the existing
+    /// registration.
+    pub fn unregister_ioeventfd(
+        &self,
+        fd: &EventFd,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        self.ioeventfd_config(fd, addr, datamatch, MSHV_IOEVENTFD_FLAG_DEASSIGN)
+    }
+
+    fn ioeventfd_config(
+        &self,
+        fd: &EventFd,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+        extra_flags: u32,
+    ) -> Result<()> {
+        let (gpa, is_pio) = match addr {
+            IoEventAddress::Mmio(gpa) => (gpa, false),
+            IoEventAddress::Pio(port) => (port, true),
+        };
+        let mut flags = extra_flags;
+        if is_pio {
+            flags |= MSHV_IOEVENTFD_FLAG_PIO;
+        }
+        if !datamatch.is_empty() {
+            flags |= MSHV_IOEVENTFD_FLAG_DATAMATCH;
+        }
+        let args = mshv_user_ioeventfd {
+            fd: fd.as_raw_fd() as u32,
+            addr: gpa,
+            len: datamatch.len(),
+            datamatch: datamatch.data(),
+            flags,
+        };
+        // SAFETY: IOCTL call with the correct types.
+        let ret = unsafe { ioctl_with_ref(&self.vm, MSHV_SET_IOEVENTFD(), &args) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
+    /// Replaces this partition's GSI -> MSI message routing table.
+    ///
+    /// Each entry maps a GSI (as bound via [`VmFd::register_irqfd`]) to the
+    /// `{address, data}` MSI message a PCI device would write to deliver
+    /// that interrupt.
+    pub fn set_msi_routing(&self, entries: &[MsiRoutingEntry]) -> Result<()> {
+        let routing_entries: Vec<mshv_msi_routing_entry> = entries
+            .iter()
+            .map(|entry| mshv_msi_routing_entry {
+                gsi: entry.gsi,
+                address: entry.address,
+                data: entry.data,
+            })
+            .collect();
+        let routing = MshvMsiRoutingTable::from_entries(&routing_entries).unwrap();
+        // SAFETY: IOCTL call with the correct types.
+        let ret = unsafe {
+            ioctl_with_ref(&self.vm, MSHV_SET_MSI_ROUTING(), routing.as_fam_struct_ptr())
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+}
+
+#[cfg(feature = "hypervisor")]
+impl crate::hypervisor::Vm for VmFd {
+    type Vcpu = VcpuFd;
+
+    fn create_vcpu(&self, id: u8) -> Result<Self::Vcpu> {
+        VmFd::create_vcpu(self, id)
+    }
+
+    fn set_partition_property(&self, property: HostPartitionProperty, value: u64) -> Result<()> {
+        VmFd::set_partition_property(self, property, value)
+    }
+
+    fn save_state(
+        &self,
+        vcpus: &[Self::Vcpu],
+        msrs: &MsrList,
+        early_properties: &[HostPartitionProperty],
+    ) -> Result<PartitionState> {
+        VmFd::save_state(self, vcpus, msrs, early_properties)
+    }
+
+    fn restore_state(&self, state: &PartitionState) -> Result<Vec<Self::Vcpu>> {
+        VmFd::restore_state(self, state)
+    }
+
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        VmFd::register_irqfd(self, fd, gsi)
+    }
+
+    fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        VmFd::unregister_irqfd(self, fd, gsi)
+    }
+
+    fn register_ioeventfd(
+        &self,
+        fd: &EventFd,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        VmFd::register_ioeventfd(self, fd, addr, datamatch)
+    }
+
+    fn unregister_ioeventfd(
+        &self,
+        fd: &EventFd,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        VmFd::unregister_ioeventfd(self, fd, addr, datamatch)
+    }
+
+    fn set_msi_routing(&self, entries: &[MsiRoutingEntry]) -> Result<()> {
+        VmFd::set_msi_routing(self, entries)
+    }
+}
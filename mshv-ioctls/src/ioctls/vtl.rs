@@ -0,0 +1,117 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::Result;
+use crate::mshv_ioctls::*;
+use mshv_bindings::*;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use vmm_sys_util::errno;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+
+/// Wrapper over an MSHV VTL (Virtual Trust Level) partition fd, opened via
+/// [`Mshv::open_vtl`](crate::ioctls::system::Mshv::open_vtl).
+///
+/// This is the VTL2 counterpart to [`VmFd`](crate::ioctls::vm::VmFd): where a
+/// `VmFd` drives a root-partition child VM, a `VtlFd` is used by a paravisor
+/// running alongside a lower VTL guest to create VTL-context VPs, map
+/// VTL-protected memory, and manage VTL protections on guest memory.
+#[derive(Debug)]
+pub struct VtlFd {
+    vtl: File,
+}
+
+/// Helper function to create a new `VtlFd` from an open `/dev/mshv_vtl*` file.
+pub fn new_vtlfd(vtl: File) -> VtlFd {
+    VtlFd { vtl }
+}
+
+impl VtlFd {
+    /// Returns the raw fd backing this `VtlFd`.
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.vtl.as_raw_fd()
+    }
+
+    /// Creates a VTL-context VP with the given index and returns a
+    /// [`VtlVpFd`] that can be used to run it.
+    pub fn create_vtl_vp(&self, vp_index: u8) -> Result<VtlVpFd> {
+        let args = mshv_vtl_create_vp {
+            vp_index: vp_index as u32,
+        };
+        // SAFETY: IOCTL call with the correct types.
+        let ret = unsafe { ioctl_with_ref(&self.vtl, MSHV_VTL_CREATE_VP(), &args) };
+        if ret >= 0 {
+            // SAFETY: we verify the value of ret and we are the owners of the fd.
+            let vp_file = unsafe { File::from_raw_fd(ret) };
+            Ok(new_vtlvpfd(vp_file))
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
+    /// Maps a VTL-protected guest memory region into this partition.
+    pub fn map_memory(&self, region: &mshv_vtl_memory_range) -> Result<()> {
+        // SAFETY: IOCTL call with the correct types.
+        let ret = unsafe { ioctl_with_ref(&self.vtl, MSHV_VTL_ADD_VTL0_MEMORY(), region) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
+    /// Unmaps a previously mapped VTL-protected guest memory region.
+    pub fn unmap_memory(&self, region: &mshv_vtl_memory_range) -> Result<()> {
+        // SAFETY: IOCTL call with the correct types.
+        let ret = unsafe { ioctl_with_ref(&self.vtl, MSHV_VTL_REMOVE_VTL0_MEMORY(), region) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+
+    /// Sets the VTL access protections (read/write/execute) on the given GPA
+    /// range.
+    pub fn set_vtl_protection(&self, protection: &mshv_vtl_set_protection) -> Result<()> {
+        // SAFETY: IOCTL call with the correct types.
+        let ret = unsafe { ioctl_with_ref(&self.vtl, MSHV_VTL_SET_PROTECTION(), protection) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+}
+
+/// Wrapper over a VTL-context VP fd created via [`VtlFd::create_vtl_vp`].
+#[derive(Debug)]
+pub struct VtlVpFd {
+    vp: File,
+}
+
+/// Helper function to create a new `VtlVpFd` from an open VTL VP file.
+pub fn new_vtlvpfd(vp: File) -> VtlVpFd {
+    VtlVpFd { vp }
+}
+
+impl VtlVpFd {
+    /// Returns the raw fd backing this `VtlVpFd`.
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.vp.as_raw_fd()
+    }
+
+    /// Returns control to the lower VTL and blocks until it exits back up
+    /// (e.g. on an intercept or a VTL call), filling in `message` with the
+    /// reason for the exit.
+    pub fn vtl_return(&self, message: &mut mshv_vtl_run) -> Result<()> {
+        // SAFETY: IOCTL call with the correct types.
+        let ret = unsafe { ioctl_with_mut_ref(&self.vp, MSHV_VTL_RETURN(), message) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last().into())
+        }
+    }
+}